@@ -14,13 +14,21 @@
 
 // Import the XDP prelude which includes bindings for `mmap` datastructures, etc.
 use redbpf_probes::xdp::prelude::*;
+// `HashMap` above only supports exact-match lookups; an LPM trie additionally resolves
+// overlapping CIDR prefixes to the most specific match.
+use redbpf_probes::maps::{LpmTrie, PerCpuArray};
 
 // Import types that we share with userspace.
-use kernelspace::probe::BeIpv4Addr;
+use kernelspace::probe::{BeIpv4Addr, BeIpv6Addr, Ipv4LpmKey, PacketStat};
 
 // Declare kernel version compatibility and license.
 program!(0xFFFFFFFE, "GPL");
 
+/// EtherType value for IPv4, in host byte order.
+const ETH_P_IP: u16 = 0x0800;
+/// EtherType value for IPv6, in host byte order.
+const ETH_P_IPV6: u16 = 0x86DD;
+
 /// Count of packets by IPv4 `src` address.
 #[map]
 static mut SRC_PACKETS: HashMap<BeIpv4Addr, u32> = HashMap::with_max_entries(100);
@@ -30,20 +38,74 @@ static mut DST_PACKETS: HashMap<BeIpv4Addr, u32> = HashMap::with_max_entries(100
 /// IPv4 `src` addresses to abort packets from.
 #[map]
 static mut SRC_BLOCK: HashMap<BeIpv4Addr, bool> = HashMap::with_max_entries(100);
+/// IPv4 `src` CIDR ranges to abort packets from. Keyed by `Ipv4LpmKey` so that the kernel
+/// resolves overlapping prefixes (e.g. a `/8` and a `/16`) to the most specific match.
+#[map]
+static mut SRC_BLOCK_CIDR: LpmTrie<Ipv4LpmKey, u8> = LpmTrie::with_max_entries(100);
+/// Count of packets by IPv6 `src` address.
+#[map]
+static mut SRC_PACKETS6: HashMap<BeIpv6Addr, u32> = HashMap::with_max_entries(100);
+/// Count of packets by IPv6 `dst` address.
+#[map]
+static mut DST_PACKETS6: HashMap<BeIpv6Addr, u32> = HashMap::with_max_entries(100);
+/// IPv6 `src` addresses to abort packets from.
+#[map]
+static mut SRC_BLOCK6: HashMap<BeIpv6Addr, bool> = HashMap::with_max_entries(100);
+/// Count of packets processed by `process`, indexed by [`PacketStat`], so that operators can see
+/// how much traffic is being dropped, blocked, or skipped rather than only counting
+/// successfully-parsed IPv4/IPv6 packets. Per-CPU since XDP programs run concurrently on every
+/// core; userspace sums the per-CPU slots when reading this map.
+#[map]
+static mut PACKET_STATS: PerCpuArray<u64> = PerCpuArray::with_max_entries(PacketStat::COUNT);
+
+/// Increment the `PACKET_STATS` slot for `reason`.
+fn bump_stat(reason: PacketStat) {
+    let initial = 0;
+    // `unsafe` is used to allow read/write access to a mutable static variable, which is a
+    // potential data race in multithreaded programs; `PerCpuArray` gives each CPU its own slot to
+    // avoid that race.
+    unsafe {
+        let count = PACKET_STATS.get(reason as u32).unwrap_or(&initial);
+        PACKET_STATS.set(reason as u32, &count.saturating_add(1));
+    }
+}
 
 #[xdp]
 fn process(ctx: XdpContext) -> XdpResult {
     // Extract the source and destination IP addresses from the IPv4 header.
     //
-    // If an IPv4 header is not found or another `NetworkError` variant is reached, the packet
-    // will be allowed through without collecting additional data. Future work could include
-    // collecting statistics on malformed packets and exposing that data to userspace.
-    let (src_ip, dst_ip): (BeIpv4Addr, BeIpv4Addr) = match ctx.ip() {
+    // If an IPv4 header is not found, fall back to attempting to parse an IPv6 header before
+    // giving up. Every path through this function bumps a `PACKET_STATS` counter so operators can
+    // see how much traffic is being dropped, blocked, or skipped instead of only counting
+    // successful IPv4 parses.
+    match ctx.ip() {
         // `unsafe` is used to allow raw pointer dereference.
-        Ok(iphdr) => unsafe { ((*iphdr).saddr.into(), (*iphdr).daddr.into()) },
-        Err(_) => return Ok(XdpAction::Pass),
+        Ok(iphdr) => {
+            let (src_ip, dst_ip): (BeIpv4Addr, BeIpv4Addr) =
+                unsafe { ((*iphdr).saddr.into(), (*iphdr).daddr.into()) };
+            let action = process_ipv4(src_ip, dst_ip);
+            bump_stat(PacketStat::for_action(action));
+            return Ok(action);
+        }
+        Err(_) => {}
     };
 
+    match parse_ipv6(&ctx) {
+        Ok((src_ip, dst_ip)) => {
+            let action = process_ipv6(src_ip, dst_ip);
+            bump_stat(PacketStat::for_action(action));
+            Ok(action)
+        }
+        Err(reason) => {
+            bump_stat(reason);
+            Ok(XdpAction::Pass)
+        }
+    }
+}
+
+/// Update the `SRC_PACKETS`/`DST_PACKETS` counters for an IPv4 packet and decide whether it
+/// should be aborted based on the `SRC_BLOCK` and `SRC_BLOCK_CIDR` maps.
+fn process_ipv4(src_ip: BeIpv4Addr, dst_ip: BeIpv4Addr) -> XdpAction {
     let initial = 0;
     // `unsafe` is used to allow read/write access to a mutable static variable, which is a
     // potential data race in multithreaded programs.
@@ -54,13 +116,79 @@ fn process(ctx: XdpContext) -> XdpResult {
         DST_PACKETS.set(&dst_ip, &count.saturating_add(1));
     }
 
-    // Check if the `src` address is in the `SRC_BLOCK` map and set to `true`. If it is, abort
-    // processing of this packet.
+    // Check if the `src` address is in the `SRC_BLOCK` map and set to `true`, or matches a
+    // blocked CIDR range in `SRC_BLOCK_CIDR`. If either is true, abort processing of this packet.
     // `unsafe` is used to dereference a raw pointer to the map value.
     let default = false;
-    if unsafe { *SRC_BLOCK.get(&src_ip).unwrap_or(&default) } {
-        Ok(XdpAction::Aborted)
+    let exact_blocked = unsafe { *SRC_BLOCK.get(&src_ip).unwrap_or(&default) };
+    let cidr_blocked = unsafe { SRC_BLOCK_CIDR.get(Ipv4LpmKey::host(src_ip)).is_some() };
+    if exact_blocked || cidr_blocked {
+        XdpAction::Aborted
+    } else {
+        XdpAction::Pass
+    }
+}
+
+/// Update the `SRC_PACKETS6`/`DST_PACKETS6` counters for an IPv6 packet and decide whether it
+/// should be aborted based on the `SRC_BLOCK6` map.
+fn process_ipv6(src_ip: BeIpv6Addr, dst_ip: BeIpv6Addr) -> XdpAction {
+    let initial = 0;
+    // `unsafe` is used to allow read/write access to a mutable static variable, which is a
+    // potential data race in multithreaded programs.
+    unsafe {
+        let count = SRC_PACKETS6.get(&src_ip).unwrap_or(&initial);
+        SRC_PACKETS6.set(&src_ip, &count.saturating_add(1));
+        let count = DST_PACKETS6.get(&dst_ip).unwrap_or(&initial);
+        DST_PACKETS6.set(&dst_ip, &count.saturating_add(1));
+    }
+
+    let default = false;
+    if unsafe { *SRC_BLOCK6.get(&src_ip).unwrap_or(&default) } {
+        XdpAction::Aborted
     } else {
-        Ok(XdpAction::Pass)
+        XdpAction::Pass
+    }
+}
+
+/// Read a `*const T` at `offset` bytes into the packet, bounds-checked against the end of the
+/// packet data. This mirrors the bounds checking that `redbpf_probes` performs internally inside
+/// `XdpContext::ip()`, which we can't reuse directly because it is hard-coded to IPv4.
+fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Option<*const T> {
+    let start = ctx.data() as usize;
+    let end = ctx.data_end() as usize;
+    if start + offset + core::mem::size_of::<T>() > end {
+        return None;
+    }
+    Some((start + offset) as *const T)
+}
+
+/// Parse an IPv6 header directly out of the packet, since `redbpf_probes` only understands
+/// IPv4 via `XdpContext::ip()`. Called once `ctx.ip()` has already failed, so this is also
+/// responsible for telling apart *why* the packet wasn't usable as IPv4: its ethertype might
+/// genuinely be something other than IPv4/IPv6, or it might claim to be IPv4 but have a header too
+/// short for `ctx.ip()` to have parsed. Returns the reason parsing failed as a `PacketStat` so that
+/// `process` can count exactly why the packet didn't make it through.
+fn parse_ipv6(ctx: &XdpContext) -> Result<(BeIpv6Addr, BeIpv6Addr), PacketStat> {
+    let eth_len = core::mem::size_of::<ethhdr>();
+
+    let ethhdr = ptr_at::<ethhdr>(ctx, 0).ok_or(PacketStat::NonIpv4)?;
+    // `h_proto` is stored in network byte order.
+    let h_proto = u16::from_be(unsafe { (*ethhdr).h_proto });
+    if h_proto != ETH_P_IPV6 {
+        // `ctx.ip()` already failed to parse this packet. If its ethertype says IPv4, that means
+        // the IPv4 header itself was too short/malformed, not that the ethertype is unsupported.
+        return Err(if h_proto == ETH_P_IP {
+            PacketStat::TruncatedHeader
+        } else {
+            PacketStat::UnsupportedEtherType
+        });
+    }
+
+    let ip6hdr = ptr_at::<ipv6hdr>(ctx, eth_len).ok_or(PacketStat::TruncatedHeader)?;
+    unsafe {
+        Ok((
+            (*ip6hdr).saddr.in6_u.u6_addr8.into(),
+            (*ip6hdr).daddr.in6_u.u6_addr8.into(),
+        ))
     }
 }
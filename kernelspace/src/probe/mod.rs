@@ -16,7 +16,9 @@
 #[cfg(feature = "std")]
 extern crate std;
 #[cfg(feature = "std")]
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use redbpf_probes::xdp::prelude::XdpAction;
 
 /// Big-endian representation of an IPv4 address.
 ///
@@ -36,6 +38,12 @@ impl BeIpv4Addr {
         // need to reverse the byte order before creating the `Ipv4Addr`.
         self.0.swap_bytes().into()
     }
+
+    /// The raw bytes of this address, in the same network byte order used on the wire (and thus
+    /// by `bpf_lpm_trie_key`).
+    pub fn octets(self) -> [u8; 4] {
+        self.0.to_ne_bytes()
+    }
 }
 
 /// Conversion from `u32` to `BeIpv4Addr`.
@@ -53,6 +61,139 @@ impl From<Ipv4Addr> for BeIpv4Addr {
     }
 }
 
+/// Key type for a `BPF_MAP_TYPE_LPM_TRIE` map of IPv4 prefixes, matching the kernel's
+/// `bpf_lpm_trie_key` layout: a 4-byte `prefixlen` in host byte order, immediately followed by the
+/// address in network byte order. The kernel uses `prefixlen` to select the most specific stored prefix that
+/// matches `addr`, so overlapping entries (e.g. a `/8` and a `/16`) resolve to the more specific
+/// one.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Ipv4LpmKey {
+    pub prefix_len: u32,
+    pub addr: [u8; 4],
+}
+
+impl Ipv4LpmKey {
+    /// Build a trie key matching exactly `addr`, e.g. for looking up a packet's source address.
+    pub fn host(addr: BeIpv4Addr) -> Self {
+        Self {
+            prefix_len: 32,
+            addr: addr.octets(),
+        }
+    }
+
+    /// Build a trie key for the network `addr/prefix_len`, masking any host bits in `addr` so
+    /// that overlapping lookups resolve correctly regardless of which bits the caller passed in.
+    ///
+    /// `prefix_len` is clamped to the valid `0..=32` range rather than trusted, since callers may
+    /// construct a `Command::BlockSrcCidr`/`UnblockSrcCidr` directly via serde (bypassing
+    /// `control::parse_cidr`'s own range check), and `32 - prefix_len` would otherwise underflow
+    /// for any `prefix_len > 32`.
+    #[cfg(feature = "std")]
+    pub fn network(addr: Ipv4Addr, prefix_len: u8) -> Self {
+        let prefix_len = prefix_len.min(32);
+        let mask = if prefix_len == 0 {
+            0
+        } else {
+            u32::MAX << (32 - prefix_len as u32)
+        };
+        let masked = u32::from_be_bytes(addr.octets()) & mask;
+        Self {
+            prefix_len: prefix_len as u32,
+            addr: masked.to_be_bytes(),
+        }
+    }
+}
+
+/// Big-endian representation of an IPv6 address.
+///
+/// Unlike [`BeIpv4Addr`], a 16-byte address cannot be byte-swapped with a single primitive
+/// operation, so we store the octets exactly as they appear on the wire (network byte order).
+/// Conversions to/from [`Ipv6Addr`] copy the octets unchanged, since its `octets()`/`from()` are
+/// also expressed in network byte order.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct BeIpv6Addr([u8; 16]);
+
+impl BeIpv6Addr {
+    /// Conversion from `BeIpv6Addr` to `std::net::Ipv6Addr`.
+    #[cfg(feature = "std")]
+    pub fn to_ip(self) -> Ipv6Addr {
+        self.0.into()
+    }
+}
+
+/// Conversion from `[u8; 16]` to `BeIpv6Addr`.
+impl From<[u8; 16]> for BeIpv6Addr {
+    fn from(be: [u8; 16]) -> Self {
+        Self(be)
+    }
+}
+
+/// Conversion from `Ipv6Addr` to `BeIpv6Addr`.
+#[cfg(feature = "std")]
+impl From<Ipv6Addr> for BeIpv6Addr {
+    fn from(ip: Ipv6Addr) -> Self {
+        Self(ip.octets())
+    }
+}
+
+/// The reason a packet took a given path through `process`, used to index the `PACKET_STATS`
+/// per-CPU counter map so operators can see how much traffic is being dropped, blocked, or
+/// skipped instead of only counting successfully-parsed IPv4/IPv6 packets.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PacketStat {
+    /// The packet was too short to contain an Ethernet header, so it parsed as neither IPv4 nor
+    /// IPv6.
+    NonIpv4 = 0,
+    /// The packet's ethertype indicated IPv4 or IPv6, but the packet was too short to contain a
+    /// full header of that type.
+    TruncatedHeader = 1,
+    /// The packet was neither IPv4 nor IPv6.
+    UnsupportedEtherType = 2,
+    /// The packet was parsed and allowed through.
+    PassedClean = 3,
+    /// The packet was parsed and aborted because its source address is blocked.
+    Blocked = 4,
+}
+
+impl PacketStat {
+    /// The number of `PacketStat` variants, i.e. the number of slots `PACKET_STATS` needs.
+    pub const COUNT: u32 = 5;
+
+    /// All variants, in the order of their discriminants, for iterating the counter map from
+    /// userspace.
+    #[cfg(feature = "std")]
+    pub const ALL: [PacketStat; Self::COUNT as usize] = [
+        PacketStat::NonIpv4,
+        PacketStat::TruncatedHeader,
+        PacketStat::UnsupportedEtherType,
+        PacketStat::PassedClean,
+        PacketStat::Blocked,
+    ];
+
+    /// A human-readable name for this reason, used to render `Command::Stats` replies.
+    #[cfg(feature = "std")]
+    pub fn name(self) -> &'static str {
+        match self {
+            PacketStat::NonIpv4 => "non_ipv4",
+            PacketStat::TruncatedHeader => "truncated_header",
+            PacketStat::UnsupportedEtherType => "unsupported_ethertype",
+            PacketStat::PassedClean => "passed_clean",
+            PacketStat::Blocked => "blocked",
+        }
+    }
+
+    /// The `PacketStat` that a successfully-parsed IPv4/IPv6 packet's `XdpAction` should count as.
+    pub fn for_action(action: XdpAction) -> PacketStat {
+        match action {
+            XdpAction::Aborted => PacketStat::Blocked,
+            _ => PacketStat::PassedClean,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -76,5 +217,63 @@ mod tests {
             let converted_beaddr: BeIpv4Addr = original_beaddr.to_ip().into();
             assert_eq!(original_beaddr, converted_beaddr);
         }
+
+        /// Test that the conversion between `BeIpv6Addr` and `std::net::Ipv6Addr` is a
+        /// [bijective function], mirroring `beipv4addr_u32_roundtrip` above.
+        ///
+        /// [bijective function]: https://en.wikipedia.org/wiki/Bijection
+        #[test]
+        #[cfg(feature="std")]
+        fn beipv6addr_octets_roundtrip(be: [u8; 16]) {
+            let original_beaddr = BeIpv6Addr(be);
+            let converted_beaddr: BeIpv6Addr = original_beaddr.to_ip().into();
+            assert_eq!(original_beaddr, converted_beaddr);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn ipv4lpmkey_network_masks_host_bits() {
+        // `10.1.2.3/8` should mask down to the `10.0.0.0` network.
+        let key = Ipv4LpmKey::network(Ipv4Addr::new(10, 1, 2, 3), 8);
+        assert_eq!(key.prefix_len, 8);
+        assert_eq!(key.addr, [10, 0, 0, 0]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn ipv4lpmkey_network_prefix_zero_masks_everything() {
+        let key = Ipv4LpmKey::network(Ipv4Addr::new(192, 168, 1, 1), 0);
+        assert_eq!(key.prefix_len, 0);
+        assert_eq!(key.addr, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn ipv4lpmkey_network_prefix_thirty_two_keeps_full_address() {
+        let key = Ipv4LpmKey::network(Ipv4Addr::new(172, 16, 0, 5), 32);
+        assert_eq!(key.prefix_len, 32);
+        assert_eq!(key.addr, [172, 16, 0, 5]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn ipv4lpmkey_network_clamps_out_of_range_prefix() {
+        // A binary codec can deserialize a `Command::BlockSrcCidr` straight from the wire,
+        // bypassing `parse_cidr`'s own range check, so an out-of-range `prefix_len` must not
+        // underflow the mask shift here.
+        let key = Ipv4LpmKey::network(Ipv4Addr::new(10, 1, 2, 3), 255);
+        assert_eq!(key.prefix_len, 32);
+        assert_eq!(key.addr, [10, 1, 2, 3]);
+    }
+
+    #[test]
+    fn packetstat_for_action_counts_aborted_as_blocked() {
+        assert_eq!(PacketStat::for_action(XdpAction::Aborted), PacketStat::Blocked);
+    }
+
+    #[test]
+    fn packetstat_for_action_counts_pass_as_passed_clean() {
+        assert_eq!(PacketStat::for_action(XdpAction::Pass), PacketStat::PassedClean);
     }
 }
\ No newline at end of file
@@ -0,0 +1,182 @@
+//! Wire codecs for the control protocol.
+//!
+//! The control socket's wire encoding is selectable at build time via Cargo features:
+//! `proto_text` (default), `proto_json`, `proto_msgpack`, and `proto_bincode`. Exactly one should
+//! be enabled; [`ControlConnection`](crate::control::ControlConnection) is generic over whichever
+//! [`ControlCodec`] implementation the build selected, aliased here as [`SelectedCodec`].
+//!
+//! `Command` and `Reply` (see `control`) are the shared data model: every codec decodes a
+//! `Command` and encodes a `Reply`, so adding a new wire format never requires touching the
+//! command-handling logic in `control` or `main`.
+
+use crate::control::{parse_text_command, Command, OutputFormat, Reply};
+
+/// Encodes/decodes the control protocol's wire representation of [`Command`] and [`Reply`].
+pub trait ControlCodec {
+    /// Whether a command arrives as a length-prefixed binary frame (`true`) rather than a
+    /// newline-terminated line (`false`, used by [`TextCodec`]). See
+    /// [`ControlConnection::read_input`](crate::control::ControlConnection::read_input).
+    const FRAMED: bool;
+
+    /// Parse a `Command` out of one input unit: a line for `TextCodec`, a full frame for binary
+    /// codecs.
+    fn decode(input: &[u8]) -> Result<Command, String>;
+
+    /// Render a `Reply` into the bytes written back to the client. `format` is only honored by
+    /// `TextCodec`, which supports switching between plain text and JSON at runtime via
+    /// `--format`; binary codecs always encode in their one wire format.
+    fn encode(reply: &Reply, format: OutputFormat) -> Vec<u8>;
+}
+
+/// The original line-oriented ASCII protocol. Default, and the only codec that supports the
+/// runtime `--format json` toggle and `--format`/`session` connection directives.
+#[cfg(feature = "proto_text")]
+pub struct TextCodec;
+
+#[cfg(feature = "proto_text")]
+impl ControlCodec for TextCodec {
+    const FRAMED: bool = false;
+
+    fn decode(input: &[u8]) -> Result<Command, String> {
+        let line = std::str::from_utf8(input).map_err(|e| e.to_string())?;
+        parse_text_command(line)
+    }
+
+    fn encode(reply: &Reply, format: OutputFormat) -> Vec<u8> {
+        match format {
+            OutputFormat::Text => reply.to_text().into_bytes(),
+            OutputFormat::Json => reply.to_json().into_bytes(),
+        }
+    }
+}
+
+/// A codec that decodes/encodes `Command`/`Reply` directly as JSON, one object per frame,
+/// instead of going through the ASCII command syntax.
+#[cfg(feature = "proto_json")]
+pub struct JsonCodec;
+
+#[cfg(feature = "proto_json")]
+impl ControlCodec for JsonCodec {
+    const FRAMED: bool = true;
+
+    fn decode(input: &[u8]) -> Result<Command, String> {
+        serde_json::from_slice(input).map_err(|e| e.to_string())
+    }
+
+    fn encode(reply: &Reply, _format: OutputFormat) -> Vec<u8> {
+        serde_json::to_vec(reply)
+            .unwrap_or_else(|e| format!(r#"{{"status":"error","message":"{}"}}"#, e).into_bytes())
+    }
+}
+
+/// A codec that decodes/encodes `Command`/`Reply` as MessagePack, for clients that want a compact
+/// binary representation without hand-rolling framing over JSON.
+#[cfg(feature = "proto_msgpack")]
+pub struct MsgpackCodec;
+
+#[cfg(feature = "proto_msgpack")]
+impl ControlCodec for MsgpackCodec {
+    const FRAMED: bool = true;
+
+    fn decode(input: &[u8]) -> Result<Command, String> {
+        rmp_serde::from_slice(input).map_err(|e| e.to_string())
+    }
+
+    fn encode(reply: &Reply, _format: OutputFormat) -> Vec<u8> {
+        rmp_serde::to_vec(reply).unwrap_or_else(|_| Vec::new())
+    }
+}
+
+/// A codec that decodes/encodes `Command`/`Reply` via `bincode`, for clients willing to couple to
+/// the daemon's exact in-memory layout in exchange for minimal overhead.
+#[cfg(feature = "proto_bincode")]
+pub struct BincodeCodec;
+
+#[cfg(feature = "proto_bincode")]
+impl ControlCodec for BincodeCodec {
+    const FRAMED: bool = true;
+
+    fn decode(input: &[u8]) -> Result<Command, String> {
+        bincode::deserialize(input).map_err(|e| e.to_string())
+    }
+
+    fn encode(reply: &Reply, _format: OutputFormat) -> Vec<u8> {
+        bincode::serialize(reply).unwrap_or_else(|_| Vec::new())
+    }
+}
+
+/// The codec selected for this build. Exactly one `proto_*` feature should be enabled;
+/// `proto_text` is the default.
+#[cfg(feature = "proto_text")]
+pub type SelectedCodec = TextCodec;
+#[cfg(all(not(feature = "proto_text"), feature = "proto_json"))]
+pub type SelectedCodec = JsonCodec;
+#[cfg(all(
+    not(feature = "proto_text"),
+    not(feature = "proto_json"),
+    feature = "proto_msgpack"
+))]
+pub type SelectedCodec = MsgpackCodec;
+#[cfg(all(
+    not(feature = "proto_text"),
+    not(feature = "proto_json"),
+    not(feature = "proto_msgpack"),
+    feature = "proto_bincode"
+))]
+pub type SelectedCodec = BincodeCodec;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    #[cfg(feature = "proto_text")]
+    fn text_codec_round_trips_command_and_reply() {
+        let command = TextCodec::decode(b"block-src 127.0.0.1").unwrap();
+        assert_eq!(command, Command::BlockSrc(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))));
+
+        let reply = Reply::IpCounts(vec![(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 3)]);
+        let encoded = TextCodec::encode(&reply, OutputFormat::Text);
+        assert_eq!(encoded, reply.to_text().into_bytes());
+    }
+
+    #[test]
+    #[cfg(feature = "proto_json")]
+    fn json_codec_round_trips_command_and_reply() {
+        let command = Command::BlockSrcCidr(Ipv4Addr::new(10, 0, 0, 0), 8);
+        let bytes = serde_json::to_vec(&command).unwrap();
+        assert_eq!(JsonCodec::decode(&bytes).unwrap(), command);
+
+        let reply = Reply::Ok;
+        let encoded = JsonCodec::encode(&reply, OutputFormat::Text);
+        let decoded: Reply = serde_json::from_slice(&encoded).unwrap();
+        assert_eq!(decoded, reply);
+    }
+
+    #[test]
+    #[cfg(feature = "proto_msgpack")]
+    fn msgpack_codec_round_trips_command_and_reply() {
+        let command = Command::UnblockSrcCidr(Ipv4Addr::new(192, 168, 0, 0), 16);
+        let bytes = rmp_serde::to_vec(&command).unwrap();
+        assert_eq!(MsgpackCodec::decode(&bytes).unwrap(), command);
+
+        let reply = Reply::BlockList(vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        let encoded = MsgpackCodec::encode(&reply, OutputFormat::Text);
+        let decoded: Reply = rmp_serde::from_slice(&encoded).unwrap();
+        assert_eq!(decoded, reply);
+    }
+
+    #[test]
+    #[cfg(feature = "proto_bincode")]
+    fn bincode_codec_round_trips_command_and_reply() {
+        let command = Command::ListSrcIps;
+        let bytes = bincode::serialize(&command).unwrap();
+        assert_eq!(BincodeCodec::decode(&bytes).unwrap(), command);
+
+        let reply = Reply::Error("boom".to_string());
+        let encoded = BincodeCodec::encode(&reply, OutputFormat::Text);
+        let decoded: Reply = bincode::deserialize(&encoded).unwrap();
+        assert_eq!(decoded, reply);
+    }
+}
@@ -16,32 +16,213 @@
 //! 6. The reply is read from the oneshot channel and written to the Unix socket.
 //! 7. The unix socket is closed.
 //!
+//! In a `session` (and always with a framed codec), steps 2 to 6 repeat for each command until the
+//! client sends `quit` or closes the connection; only then is the socket closed.
+//!
+//! # Wire encoding
+//! The bytes that actually cross the socket are produced by whichever [`ControlCodec`] (see the
+//! `codec` module) was selected at build time via the `proto_text`/`proto_json`/`proto_msgpack`/
+//! `proto_bincode` Cargo features. `Command` and `Reply` are the shared data model that every
+//! codec decodes/encodes.
 use std::error::Error;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::Path;
 
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufStream};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
 use tokio::net::{UnixListener, UnixStream};
 use tokio::sync::{mpsc, oneshot};
 
+use crate::codec::{ControlCodec, SelectedCodec};
+
 /// The set of commands that may be issued via the control socket.
 ///
 /// Rust enums allow associated data with the variants (i.e., [sum types]), which is used here to
 /// pass parameters to the processing task.
 ///
 /// [sum types]: https://en.wikipedia.org/wiki/Algebraic_data_type
+#[cfg_attr(
+    any(feature = "proto_json", feature = "proto_msgpack", feature = "proto_bincode"),
+    derive(serde::Serialize, serde::Deserialize)
+)]
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Command {
     ListSrcIps,
     ListDstIps,
     ListBlockSrc,
-    BlockSrc(Ipv4Addr),
+    BlockSrc(IpAddr),
+    /// Block an IPv4 CIDR range, e.g. `10.0.0.0/8`.
+    BlockSrcCidr(Ipv4Addr, u8),
+    /// Remove a previously blocked IPv4 CIDR range.
+    UnblockSrcCidr(Ipv4Addr, u8),
+    /// Report the `PACKET_STATS` counters, aggregated across CPUs.
+    Stats,
+}
+
+/// The outcome of executing a [`Command`], kept structured rather than pre-rendered to text so
+/// that each [`ControlCodec`] can render it in its own wire format.
+#[cfg_attr(
+    any(feature = "proto_json", feature = "proto_msgpack", feature = "proto_bincode"),
+    derive(serde::Serialize, serde::Deserialize)
+)]
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    /// A list of IP addresses and an associated packet count.
+    IpCounts(Vec<(IpAddr, u32)>),
+    /// A list of blocked IP addresses, with no associated count.
+    BlockList(Vec<IpAddr>),
+    /// The `PACKET_STATS` counters, named by reason and summed across CPUs.
+    Stats(Vec<(String, u64)>),
+    /// A command completed successfully with no data to report.
+    Ok,
+    /// A command failed; the string is a human-readable explanation.
+    Error(String),
+}
+
+impl Reply {
+    /// Render this reply the way the control socket has always rendered replies: tab-separated
+    /// `ip\tcount\n` lines, one `ip\n` per line for a block list, `ok\n`, or a bare error line.
+    pub(crate) fn to_text(&self) -> String {
+        match self {
+            Reply::IpCounts(items) => items
+                .iter()
+                .fold(String::new(), |buf, (ip, count)| {
+                    format!("{}{}\t{}\n", buf, ip, count)
+                }),
+            Reply::BlockList(items) => items
+                .iter()
+                .fold(String::new(), |buf, ip| format!("{}{}\n", buf, ip)),
+            Reply::Stats(items) => items
+                .iter()
+                .fold(String::new(), |buf, (reason, count)| {
+                    format!("{}{}\t{}\n", buf, reason, count)
+                }),
+            Reply::Ok => "ok\n".to_string(),
+            Reply::Error(message) => format!("{}\n", message),
+        }
+    }
+
+    /// Render this reply as a single line of JSON, for clients that requested
+    /// `OutputFormat::Json` within the `proto_text` wire format.
+    pub(crate) fn to_json(&self) -> String {
+        match self {
+            Reply::IpCounts(items) => {
+                let body = items
+                    .iter()
+                    .map(|(ip, count)| format!(r#"{{"ip":"{}","count":{}}}"#, ip, count))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                format!("[{}]\n", body)
+            }
+            Reply::BlockList(items) => {
+                let body = items
+                    .iter()
+                    .map(|ip| format!(r#"{{"ip":"{}"}}"#, ip))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                format!("[{}]\n", body)
+            }
+            Reply::Stats(items) => {
+                let body = items
+                    .iter()
+                    .map(|(reason, count)| format!(r#"{{"reason":"{}","count":{}}}"#, reason, count))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                format!("[{}]\n", body)
+            }
+            Reply::Ok => r#"{"status":"ok"}"#.to_string() + "\n",
+            Reply::Error(message) => {
+                // `message` comes from arbitrary `Display`/`Error` output, which may contain
+                // quotes, backslashes, or control characters; let `serde_json` produce a properly
+                // escaped JSON string instead of hand-rolling escaping.
+                let escaped = serde_json::to_string(message).expect("String always serializes");
+                format!(r#"{{"status":"error","message":{}}}"#, escaped) + "\n"
+            }
+        }
+    }
+
+    /// Render this reply according to the requested `format`.
+    fn render(&self, format: OutputFormat) -> String {
+        match format {
+            OutputFormat::Text => self.to_text(),
+            OutputFormat::Json => self.to_json(),
+        }
+    }
+}
+
+/// Parse a `<ipv4>/<prefix_len>` string, e.g. `10.0.0.0/8`, as used by `block-src` and
+/// `unblock-src` for CIDR ranges.
+fn parse_cidr(input: &str) -> Result<(Ipv4Addr, u8), Box<dyn Error>> {
+    let mut parts = input.splitn(2, '/');
+    let addr = parts.next().unwrap_or("").parse::<Ipv4Addr>()?;
+    let prefix_len = parts
+        .next()
+        .ok_or("missing prefix length")?
+        .parse::<u8>()?;
+    if prefix_len > 32 {
+        return Err("prefix length must be between 0 and 32".into());
+    }
+    Ok((addr, prefix_len))
 }
 
 #[derive(Debug)]
 pub struct CommandRequest {
     pub command: Command,
-    pub reply: oneshot::Sender<String>,
+    pub reply: oneshot::Sender<Reply>,
+}
+
+/// The wire format used to render replies on a control connection.
+///
+/// Defaults to [`OutputFormat::Text`] so that existing clients (e.g. HAProxy-style single-command
+/// usage) keep working without requesting anything.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Text
+    }
+}
+
+/// Parse a line of the `proto_text` wire format into a [`Command`].
+///
+/// This is the only place that understands the ASCII `list-src`/`block-src`/... command syntax;
+/// binary codecs (see the `codec` module) decode a `Command` directly via `serde` instead.
+pub(crate) fn parse_text_command(line: &str) -> Result<Command, String> {
+    let (cmd, params) = {
+        let mut parts = line.splitn(2, ' ');
+        (parts.next().unwrap(), parts.next())
+    };
+
+    match (cmd, params) {
+        ("list-src", None) => Ok(Command::ListSrcIps),
+        ("list-dst", None) => Ok(Command::ListDstIps),
+        ("list-block-src", None) => Ok(Command::ListBlockSrc),
+        ("stats", None) => Ok(Command::Stats),
+        ("block-src", Some(arg)) if arg.contains('/') => {
+            let (addr, prefix_len) =
+                parse_cidr(arg).map_err(|e| format!("could not parse cidr: {}", e))?;
+            Ok(Command::BlockSrcCidr(addr, prefix_len))
+        }
+        ("unblock-src", Some(arg)) => {
+            let (addr, prefix_len) =
+                parse_cidr(arg).map_err(|e| format!("could not parse cidr: {}", e))?;
+            Ok(Command::UnblockSrcCidr(addr, prefix_len))
+        }
+        ("block-src", Some(ip)) => {
+            let parsed_ip = ip
+                .parse()
+                .map_err(|e| format!("could not parse ip: {}", e))?;
+            Ok(Command::BlockSrc(parsed_ip))
+        }
+        ("list-src", Some(_)) | ("list-dst", Some(_)) => Err("unexpected parameters".into()),
+        ("block-src", None) | ("unblock-src", None) => {
+            Err("command requires parameters".into())
+        }
+        (_, _) => Err("invalid command".into()),
+    }
 }
 
 /// Bind a unix socket and accept connections in a loop. Spawn new task to process commands on
@@ -61,7 +242,7 @@ pub async fn control_socket_accept_loop(commands_tx: mpsc::Sender<CommandRequest
                 tokio::spawn(async move {
                     println!(
                         "control socket closed: {:?}",
-                        ControlConnection::new(stream, cloned_commands_tx)
+                        ControlConnection::<SelectedCodec>::new(stream, cloned_commands_tx)
                             .process_command()
                             .await
                     );
@@ -74,73 +255,144 @@ pub async fn control_socket_accept_loop(commands_tx: mpsc::Sender<CommandRequest
     }
 }
 
-/// Holds state for a connection to the control socket.
-pub struct ControlConnection {
+/// Largest payload a framed command/reply may declare in its `+<len>\n` header. Caps the
+/// allocation `read_input` makes before any of the payload has actually arrived, so a malformed or
+/// malicious header (e.g. `+99999999999\n`) can't be used to exhaust memory.
+const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// Holds state for a connection to the control socket, generic over the [`ControlCodec`] chosen
+/// at build time via the `proto_text`/`proto_json`/`proto_msgpack`/`proto_bincode` features.
+pub struct ControlConnection<C> {
     /// This channel is used to send commands to the main loop for processing.
     commands_tx: mpsc::Sender<CommandRequest>,
     /// The unix socket stream, used to receive control commands and write output.
     stream: BufStream<UnixStream>,
+    /// The format used to render replies on this connection when using `TextCodec`. Selected
+    /// with a standalone `--format text` or `--format json` line, which is acknowledged with an
+    /// `ok` reply; defaults to [`OutputFormat::Text`]. Ignored
+    /// by binary codecs, which always reply in their own wire format.
+    format: OutputFormat,
+    /// Whether this connection is in pipelined, multi-command mode. For `TextCodec` this is
+    /// negotiated with the `session` command; framed (binary) codecs are always pipelined. Once
+    /// set, replies are length-framed (see [`ControlConnection::write_reply`]) so a client
+    /// issuing several commands over the same connection can tell where one reply ends and the
+    /// next begins.
+    session: bool,
+    codec: std::marker::PhantomData<C>,
 }
 
-impl ControlConnection {
+impl<C: ControlCodec> ControlConnection<C> {
     /// Create a new `ControlConnection` for an accepted unix socket connection.
     pub fn new(stream: UnixStream, commands_tx: mpsc::Sender<CommandRequest>) -> Self {
         Self {
             commands_tx,
             stream: BufStream::new(stream),
+            format: OutputFormat::default(),
+            session: false,
+            codec: std::marker::PhantomData,
+        }
+    }
+
+    /// Write a reply to the socket, encoded by `C`.
+    ///
+    /// Outside of a negotiated session, a reply is written as-is, matching the original
+    /// single-command-per-connection protocol. Inside a session (always true for framed, binary
+    /// codecs), each reply is prefixed with a `+<len>\n` framing header giving the byte length of
+    /// the payload that follows, so a client reading several replies off one stream knows where
+    /// each one ends.
+    async fn write_reply(&mut self, reply: &Reply) -> Result<(), Box<dyn Error>> {
+        let body = C::encode(reply, self.format);
+        if self.session || C::FRAMED {
+            self.stream
+                .write_all(format!("+{}\n", body.len()).as_bytes())
+                .await?;
         }
+        self.stream.write_all(&body).await?;
+        self.stream.flush().await?;
+        Ok(())
     }
 
-    /// Read a single command from the unix socket, queue that command, and write any response to
-    /// the control socket.
+    /// Read one unit of input from the socket: a newline-terminated line for text codecs, or a
+    /// `+<len>\n`-framed payload for binary codecs. Returns `None` at EOF.
+    async fn read_input(&mut self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        if C::FRAMED {
+            let mut header = String::new();
+            if self.stream.read_line(&mut header).await? == 0 {
+                return Ok(None);
+            }
+            let len: usize = header
+                .strip_prefix('+')
+                .and_then(|rest| rest.trim_end().parse().ok())
+                .ok_or("malformed frame header")?;
+            if len > MAX_FRAME_LEN {
+                self.write_reply(&Reply::Error(format!(
+                    "frame length {} exceeds maximum of {} bytes",
+                    len, MAX_FRAME_LEN
+                )))
+                .await?;
+                return Err("frame length exceeds maximum".into());
+            }
+            let mut payload = vec![0u8; len];
+            self.stream.read_exact(&mut payload).await?;
+            Ok(Some(payload))
+        } else {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Ok(None);
+            }
+            Ok(Some(line.trim_end().as_bytes().to_vec()))
+        }
+    }
+
+    /// Read commands from the unix socket, queue each one, and write its response to the control
+    /// socket.
+    ///
+    /// With `TextCodec`, only one command is processed before the connection closes by default. A
+    /// client that opens with the `session` command switches into a pipelined, multi-command mode
+    /// in which commands are read and replied to in a loop until the client sends `quit` or closes
+    /// the connection. Framed (binary) codecs are always pipelined this way.
     pub async fn process_command(&mut self) -> Result<(), Box<dyn Error>> {
-        let mut cmd_str = String::new();
         loop {
-            // Read a line from the socket.
-            cmd_str.clear();
-            if self.stream.read_line(&mut cmd_str).await? == 0 {
-                return Ok(()); // Reached EOF
-            }
-            let (cmd, params) = {
-                // Remove trailing whitespace (likely `\r\n`).
-                let cmd_slice = cmd_str.trim_end();
-                let mut parts = cmd_slice.splitn(2, ' ');
-                (parts.next().unwrap(), parts.next())
+            let input = match self.read_input().await? {
+                Some(input) => input,
+                None => return Ok(()), // Reached EOF
             };
 
-            // Parse parameters and create `Command`
-            let command = match (cmd, params) {
-                ("list-src", None) => Command::ListSrcIps,
-                ("list-dst", None) => Command::ListDstIps,
-                ("list-block-src", None) => Command::ListBlockSrc,
-                ("block-src", Some(ip)) => {
-                    let parsed_ip = match ip.parse() {
-                        Ok(parsed) => parsed,
-                        Err(e) => {
-                            self.stream
-                                .write_all(format!("could not parse ip: {}\n", e).as_bytes())
-                                .await?;
-                            self.stream.flush().await?;
-                            return Err("invalid ip address".into());
-                        }
-                    };
-                    Command::BlockSrc(parsed_ip)
+            // `session`/`quit`/`--format` are connection-level directives rather than
+            // `Command`s, and only make sense for the line-oriented text protocol.
+            if !C::FRAMED {
+                let line = String::from_utf8_lossy(&input).into_owned();
+                if line == "session" {
+                    self.session = true;
+                    self.write_reply(&Reply::Ok).await?;
+                    continue;
                 }
-                ("list-src", Some(_)) | ("list-dst", Some(_)) => {
-                    self.stream.write_all(b"unexpected parameters\n").await?;
-                    self.stream.flush().await?;
-                    return Err("unexpected parameters".into());
+                if self.session && line == "quit" {
+                    self.write_reply(&Reply::Ok).await?;
+                    return Ok(());
                 }
-                ("block-src", None) => {
-                    self.stream
-                        .write_all(b"command requires parameters\n")
-                        .await?;
-                    self.stream.flush().await?;
-                    return Err("command requires parameters".into());
+                if let Some(format) = line.strip_prefix("--format ") {
+                    self.format = match format {
+                        "text" => OutputFormat::Text,
+                        "json" => OutputFormat::Json,
+                        _ => {
+                            self.write_reply(&Reply::Error(format!(
+                                "unknown format: {}",
+                                format
+                            )))
+                            .await?;
+                            return Err("unknown format".into());
+                        }
+                    };
+                    self.write_reply(&Reply::Ok).await?;
+                    continue;
                 }
-                (_, _) => {
-                    self.stream.write_all(b"invalid command\n").await?;
-                    self.stream.flush().await?;
+            }
+
+            let command = match C::decode(&input) {
+                Ok(command) => command,
+                Err(message) => {
+                    self.write_reply(&Reply::Error(message)).await?;
                     return Err("invalid command".into());
                 }
             };
@@ -153,13 +405,113 @@ impl ControlConnection {
                     reply: reply_tx,
                 })
                 .await?;
-            self.stream.write_all(reply_rx.await?.as_bytes()).await?;
-            self.stream.flush().await?;
+            let reply = reply_rx.await?;
+            self.write_reply(&reply).await?;
 
-            // Currently, only one command may be issued for each connection. This eases parsing
+            // Outside of a negotiated `session` (or a framed binary codec, which is always
+            // pipelined), only one command may be issued for each connection. This eases parsing
             // of the response because there is no need to build specific mechanisms to separate
             // responses in the stream. (HAProxy behaves likewise for its control socket.)
-            return Ok(());
+            if !self.session && !C::FRAMED {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Drive a `session`-mode connection through several pipelined commands over one
+    /// `UnixStream` and assert that each framed reply is delimited correctly.
+    #[tokio::test]
+    async fn pipelined_session_frames_each_reply() {
+        let (client, server) = UnixStream::pair().expect("failed to create socket pair");
+        let (commands_tx, mut commands_rx) = mpsc::channel(8);
+
+        // Stand in for the main loop: reply `Ok` to every queued command.
+        tokio::spawn(async move {
+            while let Some(request) = commands_rx.recv().await {
+                let _ = request.reply.send(Reply::Ok);
+            }
+        });
+
+        tokio::spawn(async move {
+            ControlConnection::<crate::codec::SelectedCodec>::new(server, commands_tx)
+                .process_command()
+                .await
+        });
+
+        let mut client = BufStream::new(client);
+        client.write_all(b"session\n").await.unwrap();
+        client.write_all(b"block-src 127.0.0.1\n").await.unwrap();
+        client.write_all(b"block-src 127.0.0.2\n").await.unwrap();
+        client.write_all(b"quit\n").await.unwrap();
+        client.flush().await.unwrap();
+
+        for _ in 0..4 {
+            let mut header = String::new();
+            client.read_line(&mut header).await.unwrap();
+            let len: usize = header
+                .strip_prefix('+')
+                .and_then(|rest| rest.trim_end().parse().ok())
+                .expect("reply should start with a `+<len>` framing header");
+
+            let mut payload = vec![0u8; len];
+            client.read_exact(&mut payload).await.unwrap();
+            assert_eq!(payload, Reply::Ok.to_text().into_bytes());
         }
     }
+
+    #[test]
+    fn parse_cidr_accepts_prefix_zero_and_thirty_two() {
+        assert_eq!(
+            parse_cidr("0.0.0.0/0").unwrap(),
+            (Ipv4Addr::new(0, 0, 0, 0), 0)
+        );
+        assert_eq!(
+            parse_cidr("10.0.0.1/32").unwrap(),
+            (Ipv4Addr::new(10, 0, 0, 1), 32)
+        );
+    }
+
+    #[test]
+    fn parse_cidr_does_not_mask_host_bits() {
+        // Masking to the network is `Ipv4LpmKey::network`'s job; `parse_cidr` just parses.
+        assert_eq!(
+            parse_cidr("10.1.2.3/8").unwrap(),
+            (Ipv4Addr::new(10, 1, 2, 3), 8)
+        );
+    }
+
+    #[test]
+    fn parse_cidr_rejects_prefix_over_32() {
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn parse_cidr_rejects_missing_slash() {
+        assert!(parse_cidr("10.0.0.0").is_err());
+    }
+
+    #[test]
+    fn parse_cidr_rejects_garbage_address() {
+        assert!(parse_cidr("not-an-ip/8").is_err());
+    }
+
+    #[test]
+    fn stats_reply_renders_as_text() {
+        let reply = Reply::Stats(vec![
+            ("passed_clean".to_string(), 42),
+            ("blocked".to_string(), 7),
+        ]);
+        assert_eq!(reply.to_text(), "passed_clean\t42\nblocked\t7\n");
+    }
+
+    #[test]
+    fn stats_reply_renders_as_json() {
+        let reply = Reply::Stats(vec![("blocked".to_string(), 7)]);
+        assert_eq!(reply.to_json(), "[{\"reason\":\"blocked\",\"count\":7}]\n");
+    }
 }
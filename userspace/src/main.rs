@@ -1,13 +1,16 @@
 use clap::{App, Arg};
 use redbpf::load::Loader;
 use redbpf::xdp::Flags;
-use redbpf::HashMap;
+use redbpf::{HashMap, LpmTrie, PerCpuArray};
 use tokio::sync::mpsc;
 
-use kernelspace::probe::BeIpv4Addr;
+use std::net::IpAddr;
 
+use kernelspace::probe::{BeIpv4Addr, BeIpv6Addr, Ipv4LpmKey, PacketStat};
+
+mod codec;
 mod control;
-use control::Command;
+use control::{Command, Reply};
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> ! {
@@ -60,6 +63,36 @@ async fn main() -> ! {
             .expect("HashMap SRC_BLOCK not found"),
     )
     .expect("error creating HashMap in userspace");
+    let src_packets6 = HashMap::<BeIpv6Addr, u32>::new(
+        loaded
+            .map("SRC_PACKETS6")
+            .expect("HashMap SRC_PACKETS6 not found"),
+    )
+    .expect("error creating HashMap in userspace");
+    let dst_packets6 = HashMap::<BeIpv6Addr, u32>::new(
+        loaded
+            .map("DST_PACKETS6")
+            .expect("HashMap DST_PACKETS6 not found"),
+    )
+    .expect("error creating HashMap in userspace");
+    let src_blocks6 = HashMap::<BeIpv6Addr, bool>::new(
+        loaded
+            .map("SRC_BLOCK6")
+            .expect("HashMap SRC_BLOCK6 not found"),
+    )
+    .expect("error creating HashMap in userspace");
+    let src_block_cidr = LpmTrie::<Ipv4LpmKey, u8>::new(
+        loaded
+            .map("SRC_BLOCK_CIDR")
+            .expect("LpmTrie SRC_BLOCK_CIDR not found"),
+    )
+    .expect("error creating LpmTrie in userspace");
+    let packet_stats = PerCpuArray::<u64>::new(
+        loaded
+            .map("PACKET_STATS")
+            .expect("PerCpuArray PACKET_STATS not found"),
+    )
+    .expect("error creating PerCpuArray in userspace");
 
     // Start accepting connections on our control socket.
     let (commands_tx, mut commands_rx) = mpsc::channel(512);
@@ -72,31 +105,73 @@ async fn main() -> ! {
     loop {
         let command_request = commands_rx.recv().await.expect("command channel closed");
         let res = match command_request.command {
-            Command::ListSrcIps => command_request.reply.send(
-                src_packets
+            Command::ListSrcIps => {
+                let counts = src_packets
                     .iter()
-                    .fold(String::new(), |buf, (be_ip, count)| {
-                        format!("{}{}\t{}\n", buf, be_ip.to_ip(), count)
-                    }),
-            ),
-            Command::ListDstIps => command_request.reply.send(
-                dst_packets
+                    .map(|(be_ip, count)| (IpAddr::V4(be_ip.to_ip()), count))
+                    .chain(
+                        src_packets6
+                            .iter()
+                            .map(|(be_ip, count)| (IpAddr::V6(be_ip.to_ip()), count)),
+                    )
+                    .collect();
+                command_request.reply.send(Reply::IpCounts(counts))
+            }
+            Command::ListDstIps => {
+                let counts = dst_packets
+                    .iter()
+                    .map(|(be_ip, count)| (IpAddr::V4(be_ip.to_ip()), count))
+                    .chain(
+                        dst_packets6
+                            .iter()
+                            .map(|(be_ip, count)| (IpAddr::V6(be_ip.to_ip()), count)),
+                    )
+                    .collect();
+                command_request.reply.send(Reply::IpCounts(counts))
+            }
+            Command::ListBlockSrc => {
+                let ips = src_blocks
                     .iter()
-                    .fold(String::new(), |buf, (be_ip, count)| {
-                        format!("{}{}\t{}\n", buf, be_ip.to_ip(), count)
-                    }),
-            ),
-            Command::ListBlockSrc => command_request.reply.send(
-                src_blocks
+                    .filter(|(_, blocked)| *blocked)
+                    .map(|(be_ip, _)| IpAddr::V4(be_ip.to_ip()))
+                    .chain(
+                        src_blocks6
+                            .iter()
+                            .filter(|(_, blocked)| *blocked)
+                            .map(|(be_ip, _)| IpAddr::V6(be_ip.to_ip())),
+                    )
+                    .collect();
+                command_request.reply.send(Reply::BlockList(ips))
+            }
+            Command::BlockSrc(IpAddr::V4(ip)) => {
+                src_blocks.set(BeIpv4Addr::from(ip), true);
+                command_request.reply.send(Reply::Ok)
+            }
+            Command::BlockSrc(IpAddr::V6(ip)) => {
+                src_blocks6.set(BeIpv6Addr::from(ip), true);
+                command_request.reply.send(Reply::Ok)
+            }
+            Command::BlockSrcCidr(addr, prefix_len) => {
+                src_block_cidr.set(Ipv4LpmKey::network(addr, prefix_len), 1);
+                command_request.reply.send(Reply::Ok)
+            }
+            Command::UnblockSrcCidr(addr, prefix_len) => {
+                src_block_cidr.delete(Ipv4LpmKey::network(addr, prefix_len));
+                command_request.reply.send(Reply::Ok)
+            }
+            Command::Stats => {
+                let counts = PacketStat::ALL
                     .iter()
-                    .fold(String::new(), |buf, (be_ip, count)| {
-                        format!("{}{}\t{}\n", buf, be_ip.to_ip(), count)
-                    }),
-            ),
-            Command::BlockSrc(ip) => {
-                let be_ip = BeIpv4Addr::from(ip);
-                src_blocks.set(be_ip, true);
-                command_request.reply.send("ok\n".into())
+                    .map(|reason| {
+                        let total: u64 = packet_stats
+                            .get(*reason as u32)
+                            .into_iter()
+                            .flatten()
+                            .sum();
+                        (reason.name().to_string(), total)
+                    })
+                    .collect();
+                command_request.reply.send(Reply::Stats(counts))
             }
         };
         if let Err(e) = res {